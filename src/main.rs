@@ -1,50 +1,363 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use clap::Parser;
+use rand::Rng;
 use reqwest::{Client, Url};
 use scraper::{Html, Selector};
-use tokio::sync::{mpsc, Mutex};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch, Mutex, Semaphore};
+use tracing::{debug, info, instrument, warn};
+
+const USER_AGENT: &str = "AsyncCrawler/1.0";
+
+/// How crawl results are reported: human-readable banners for interactive
+/// use, or newline-delimited JSON for piping into other tooling.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputMode {
+    Pretty,
+    Json,
+}
+
+/// One crawled page, serialized as a single JSON line in `OutputMode::Json`.
+#[derive(Serialize)]
+struct PageRecord {
+    url: String,
+    title: String,
+    status: u16,
+    links: Vec<String>,
+    depth: usize,
+    fetched_at: String,
+}
+
+/// A URL queued for crawling along with its distance from the seed.
+struct CrawlItem {
+    url: String,
+    depth: usize,
+}
+
+/// `robots.txt` rules scoped to our user agent, plus an optional crawl delay.
+#[derive(Default)]
+struct RobotsRules {
+    disallow: Vec<String>,
+    allow: Vec<String>,
+    crawl_delay: Option<Duration>,
+}
+
+impl RobotsRules {
+    /// True if `path` is disallowed: the longest matching `Disallow` prefix
+    /// wins unless an `Allow` prefix at least as long overrides it.
+    fn is_disallowed(&self, path: &str) -> bool {
+        let longest_disallow = self.disallow.iter().filter(|p| path.starts_with(p.as_str())).map(|p| p.len()).max();
+        let longest_allow = self.allow.iter().filter(|p| path.starts_with(p.as_str())).map(|p| p.len()).max();
+
+        match (longest_disallow, longest_allow) {
+            (Some(d), Some(a)) => d > a,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Parses a `robots.txt` body, keeping only the rules that apply to `agent`
+/// (falling back to the wildcard `*` group when there is no exact match).
+fn parse_robots(body: &str, agent: &str) -> RobotsRules {
+    let mut wildcard = RobotsRules::default();
+    let mut specific = RobotsRules::default();
+    let mut has_specific = false;
+    let mut applies_to_specific = false;
+    let mut applies_to_wildcard = false;
+
+    for raw_line in body.lines() {
+        let line = raw_line.split('#').next().unwrap_or("").trim();
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+
+        match key.as_str() {
+            "user-agent" => {
+                applies_to_wildcard = value == "*";
+                applies_to_specific = value.eq_ignore_ascii_case(agent);
+                if applies_to_specific {
+                    has_specific = true;
+                }
+            }
+            "disallow" if !value.is_empty() => {
+                if applies_to_wildcard {
+                    wildcard.disallow.push(value.to_string());
+                }
+                if applies_to_specific {
+                    specific.disallow.push(value.to_string());
+                }
+            }
+            "allow" if !value.is_empty() => {
+                if applies_to_wildcard {
+                    wildcard.allow.push(value.to_string());
+                }
+                if applies_to_specific {
+                    specific.allow.push(value.to_string());
+                }
+            }
+            "crawl-delay" => {
+                if let Ok(secs) = value.parse::<f64>() {
+                    let delay = Duration::from_secs_f64(secs);
+                    if applies_to_wildcard {
+                        wildcard.crawl_delay = Some(delay);
+                    }
+                    if applies_to_specific {
+                        specific.crawl_delay = Some(delay);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if has_specific { specific } else { wildcard }
+}
+
+/// Scales `delay` by `1.0 + jitter_fraction` (e.g. `-0.5..=0.5` for +/-50%
+/// jitter), floored at zero.
+fn apply_jitter(delay: Duration, jitter_fraction: f64) -> Duration {
+    delay.mul_f64((1.0 + jitter_fraction).max(0.0))
+}
+
+/// Computes the next exponential backoff delay, capped at `max_delay`.
+fn next_backoff_delay(current: Duration, factor: f64, max_delay: Duration) -> Duration {
+    current.mul_f64(factor).min(max_delay)
+}
+
+/// User-tunable `Crawler` settings, normally sourced from the CLI.
+struct CrawlerConfig {
+    timeout: Duration,
+    user_agent: String,
+    max_depth: usize,
+    any_domain: bool,
+    max_in_flight_requests: usize,
+    json_output: bool,
+    output_file: Option<PathBuf>,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            user_agent: USER_AGENT.to_string(),
+            max_depth: 5,
+            any_domain: false,
+            max_in_flight_requests: 10,
+            json_output: false,
+            output_file: None,
+        }
+    }
+}
 
 struct Crawler {
     client: Client,
     visited: Arc<Mutex<HashSet<String>>>,
     base_domain: String,
+    any_domain: bool,
+    user_agent: String,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    retry_factor: f64,
+    retry_max_delay: Duration,
+    robots: Mutex<HashMap<String, RobotsRules>>,
+    last_request_at: Mutex<HashMap<String, Instant>>,
+    default_crawl_delay: Duration,
+    max_depth: usize,
+    in_flight: AtomicUsize,
+    shutdown_tx: watch::Sender<bool>,
+    output_mode: OutputMode,
+    output_file: Option<std::sync::Mutex<std::fs::File>>,
+    request_semaphore: Semaphore,
 }
 
 impl Crawler {
-    pub fn new(base_url: &str) -> Self {
+    pub fn new(base_url: &str, config: CrawlerConfig) -> Self {
         let base_url = Url::parse(base_url).expect("Invalid base URL");
         let base_domain = base_url.domain().unwrap_or("").to_string();
 
         Self {
             client: Client::builder()
-                .timeout(Duration::from_secs(10))
-                .user_agent("AsyncCrawler/1.0")
+                .timeout(config.timeout)
+                .user_agent(config.user_agent.clone())
                 .build()
                 .expect("Failed to create client"),
             visited: Arc::new(Mutex::new(HashSet::new())),
             base_domain,
+            any_domain: config.any_domain,
+            user_agent: config.user_agent,
+            max_retries: 5,
+            retry_base_delay: Duration::from_millis(200),
+            retry_factor: 2.0,
+            retry_max_delay: Duration::from_secs(10),
+            robots: Mutex::new(HashMap::new()),
+            last_request_at: Mutex::new(HashMap::new()),
+            default_crawl_delay: Duration::from_millis(500),
+            max_depth: config.max_depth,
+            in_flight: AtomicUsize::new(0),
+            shutdown_tx: watch::channel(false).0,
+            output_mode: if config.json_output { OutputMode::Json } else { OutputMode::Pretty },
+            output_file: config.output_file.map(|path| {
+                std::sync::Mutex::new(
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .expect("Failed to open output file"),
+                )
+            }),
+            request_semaphore: Semaphore::new(config.max_in_flight_requests),
         }
     }
 
-    async fn fetch(&self, url: String) -> Option<String> {
-        match self.client.get(&url).send().await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    Some(response.text().await.unwrap_or_default())
-                } else {
-                    eprintln!("Request failed for {}:{}", url, response.status());
-                    None
+    /// Marks one more URL as queued for processing.
+    fn track_enqueued(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Marks a queued URL as fully processed. Once the in-flight count drops
+    /// to zero the frontier is exhausted, so wake every worker waiting for
+    /// shutdown. Uses a `watch` channel rather than `Notify` so the signal
+    /// can't be missed by a worker that subscribes after it fires: every
+    /// worker holds its own `Receiver` for the whole run, and `watch`
+    /// remembers the latest value for receivers that haven't observed it yet.
+    fn track_finished(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _ = self.shutdown_tx.send(true);
+        }
+    }
+
+    /// Fetches and parses `robots.txt` for `host` the first time we contact
+    /// it; subsequent calls for the same host reuse the cached rules. Each
+    /// host crawled gets its own rules, so following off-domain links (e.g.
+    /// via `--any-domain`) doesn't borrow the seed's robots.txt.
+    async fn ensure_robots_loaded(&self, origin: &str, host: &str) {
+        if self.robots.lock().await.contains_key(host) {
+            return;
+        }
+
+        let robots_url = format!("{}/robots.txt", origin);
+        let rules = match self.fetch(robots_url).await {
+            Some((_, body)) => parse_robots(&body, &self.user_agent),
+            None => RobotsRules::default(),
+        };
+
+        self.robots.lock().await.insert(host.to_string(), rules);
+    }
+
+    async fn is_disallowed(&self, host: &str, path: &str) -> bool {
+        self.robots
+            .lock()
+            .await
+            .get(host)
+            .map(|rules| rules.is_disallowed(path))
+            .unwrap_or(false)
+    }
+
+    /// Sleeps, if necessary, so at least one crawl-delay worth of time has
+    /// passed since the previous request to `host`. The per-host timestamp
+    /// lock is held only long enough to read/update it, not across the
+    /// sleep, so a slow crawl-delay on one host doesn't serialize requests
+    /// to every other host (or even other in-flight requests to this host).
+    async fn wait_for_politeness(&self, host: &str) {
+        let delay = self
+            .robots
+            .lock()
+            .await
+            .get(host)
+            .and_then(|rules| rules.crawl_delay)
+            .unwrap_or(self.default_crawl_delay);
+
+        let sleep_for = {
+            let mut last_request_at = self.last_request_at.lock().await;
+            let now = Instant::now();
+            let next_allowed = last_request_at
+                .get(host)
+                .map(|previous| *previous + delay)
+                .unwrap_or(now);
+            last_request_at.insert(host.to_string(), next_allowed.max(now));
+            next_allowed.saturating_duration_since(now)
+        };
+
+        if !sleep_for.is_zero() {
+            tokio::time::sleep(sleep_for).await;
+        }
+    }
+
+    /// Fetches `url`, retrying transient failures (timeouts, connection errors,
+    /// 5xx/429 responses) with exponential backoff and +/-50% jitter. 4xx
+    /// responses other than 429 are treated as permanent and return `None`
+    /// immediately. Total concurrent requests across all workers are capped
+    /// by `request_semaphore`.
+    #[instrument(skip(self), fields(%url, status, latency_ms))]
+    async fn fetch(&self, url: String) -> Option<(u16, String)> {
+        let mut delay = self.retry_base_delay;
+
+        for attempt in 1..=self.max_retries {
+            let permit = self
+                .request_semaphore
+                .acquire()
+                .await
+                .expect("request semaphore closed");
+            let started_at = Instant::now();
+            let result = self.client.get(&url).send().await;
+
+            // The permit is held until the body is fully read (or the
+            // request is abandoned), so max_in_flight_requests bounds total
+            // concurrent transfers, not just time-to-headers.
+            match result {
+                Ok(response) => {
+                    let status = response.status();
+                    let latency_ms = started_at.elapsed().as_millis();
+                    tracing::Span::current().record("status", status.as_u16());
+                    tracing::Span::current().record("latency_ms", latency_ms);
+
+                    if status.is_success() {
+                        let body = response.text().await.unwrap_or_default();
+                        drop(permit);
+                        return Some((status.as_u16(), body));
+                    }
+
+                    drop(permit);
+
+                    if status.is_client_error() && status.as_u16() != 429 {
+                        warn!(%status, "request failed permanently");
+                        return None;
+                    }
+
+                    warn!(%status, attempt, max_retries = self.max_retries, "request failed, will retry");
+                }
+                Err(e) => {
+                    drop(permit);
+
+                    if !e.is_timeout() && !e.is_connect() {
+                        warn!(error = %e, "request failed permanently");
+                        return None;
+                    }
+
+                    warn!(error = %e, attempt, max_retries = self.max_retries, "request failed, will retry");
                 }
             }
-            Err(e) => {
-                eprintln!("Request failed for {}:{}", url, e);
-                None
+
+            if attempt == self.max_retries {
+                return None;
             }
+
+            let jitter = rand::thread_rng().gen_range(-0.5..=0.5);
+            tokio::time::sleep(apply_jitter(delay, jitter)).await;
+
+            delay = next_backoff_delay(delay, self.retry_factor, self.retry_max_delay);
         }
+
+        None
     }
 
-    fn extract_links(&self, html: &str, current_url: &str) -> Vec<String> {
+    fn extract_links(&self, html: &str, current_url: &str, status: u16, depth: usize) -> Vec<String> {
         let document = Html::parse_document(html);
         let title = Selector::parse("title")
             .ok()
@@ -61,46 +374,96 @@ impl Crawler {
                 let base = Url::parse(current_url).ok()?;
                 let resolved = base.join(href).ok()?;
 
-                if resolved.domain() != Some(&self.base_domain) {
+                if !self.any_domain && resolved.domain() != Some(&self.base_domain) {
                     return None;
                 }
 
                 Some(resolved.to_string())
             }).collect();
 
-        // === 格式化输出 ===
-        println!("\n==============================");
-        println!("[✓] Crawled: {}", current_url);
-        println!("Title   : {}", title);
-        if !links.is_empty() {
-            println!("\nLinks:");
-            for link in &links {
-                println!("  - {}", link);
+        match self.output_mode {
+            OutputMode::Pretty => {
+                println!("\n==============================");
+                println!("[✓] Crawled: {}", current_url);
+                println!("Title   : {}", title);
+                if !links.is_empty() {
+                    println!("\nLinks:");
+                    for link in &links {
+                        println!("  - {}", link);
+                    }
+                } else {
+                    println!("No links found.");
+                }
+                println!("==============================\n");
+            }
+            OutputMode::Json => {
+                let record = PageRecord {
+                    url: current_url.to_string(),
+                    title,
+                    status,
+                    links: links.clone(),
+                    depth,
+                    fetched_at: Utc::now().to_rfc3339(),
+                };
+                match serde_json::to_string(&record) {
+                    Ok(line) => match &self.output_file {
+                        Some(file) => {
+                            use std::io::Write as _;
+                            let mut file = file.lock().expect("output file mutex poisoned");
+                            let _ = writeln!(file, "{}", line);
+                        }
+                        None => println!("{}", line),
+                    },
+                    Err(e) => warn!(url = %current_url, error = %e, "failed to serialize page record"),
+                }
             }
-        } else {
-            println!("No links found.");
         }
-        println!("==============================\n");
 
         links
     }
 
-    async fn process_url(&self, url: String, tx: mpsc::Sender<String>) {
-        if self.is_visited(&url).await {
+    #[instrument(skip(self, item, tx), fields(url = %item.url, depth = item.depth))]
+    async fn process_url(&self, item: CrawlItem, tx: mpsc::UnboundedSender<CrawlItem>) {
+        let CrawlItem { url, depth } = item;
+
+        if depth > self.max_depth || self.is_visited(&url).await {
+            self.track_finished();
             return;
         }
 
-        println!("Crawling: {}", url);
+        let Ok(parsed) = Url::parse(&url) else {
+            self.track_finished();
+            return;
+        };
+        let origin = parsed.origin().ascii_serialization();
+        let host = parsed.host_str().unwrap_or("").to_string();
+        let path = parsed.path().to_string();
+
+        self.ensure_robots_loaded(&origin, &host).await;
 
-        if let Some(html) = self.fetch(url.clone()).await {
+        if self.is_disallowed(&host, &path).await {
+            debug!("skipping, disallowed by robots.txt");
+            self.track_finished();
+            return;
+        }
+
+        self.wait_for_politeness(&host).await;
+
+        info!("crawling");
+
+        if let Some((status, html)) = self.fetch(url.clone()).await {
             let _ = self.mark_visited(&url).await;
 
-            for link in self.extract_links(&html, &url) {
+            for link in self.extract_links(&html, &url, status, depth) {
                 if !self.is_visited(&link).await {
-                    tx.send(link).await.expect("Failed to send link");
+                    self.track_enqueued();
+                    let child = CrawlItem { url: link, depth: depth + 1 };
+                    tx.send(child).expect("Failed to send link");
                 }
             }
         }
+
+        self.track_finished();
     }
     async fn is_visited(&self, url: &str) -> bool {
         self.visited.lock().await.contains(url)
@@ -111,38 +474,201 @@ impl Crawler {
     }
 }
 
+/// Command-line options controlling the crawl.
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Cli {
+    /// Seed URL to start crawling from.
+    #[arg(long, default_value = "https://www.bilibili.com")]
+    url: String,
+
+    /// Number of concurrent worker tasks.
+    #[arg(long, default_value_t = 5)]
+    workers: usize,
+
+    /// Per-request timeout, in seconds.
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// User-Agent string sent with every request.
+    #[arg(long, default_value = USER_AGENT)]
+    user_agent: String,
+
+    /// Maximum link depth from the seed URL.
+    #[arg(long, default_value_t = 5)]
+    max_depth: usize,
+
+    /// Follow links to other domains instead of staying on the seed's domain.
+    #[arg(long)]
+    any_domain: bool,
+
+    /// Maximum number of requests in flight at once, across all workers.
+    #[arg(long, default_value_t = 10)]
+    max_in_flight: usize,
+
+    /// Emit newline-delimited JSON crawl records instead of the interactive
+    /// pretty printer.
+    #[arg(long)]
+    json: bool,
+
+    /// Write JSON crawl records to this file instead of stdout. Implies
+    /// `--json`.
+    #[arg(long)]
+    output_file: Option<PathBuf>,
+}
+
 #[tokio::main]
 async fn main() {
-    let base_url = "https://www.bilibili.com";
-    let crawler = Arc::new(Crawler::new(base_url));
+    tracing_subscriber::fmt::init();
+
+    let cli = Cli::parse();
 
-    let (tx, rx) = mpsc::channel::<String>(100);
+    let config = CrawlerConfig {
+        timeout: Duration::from_secs(cli.timeout),
+        user_agent: cli.user_agent,
+        max_depth: cli.max_depth,
+        any_domain: cli.any_domain,
+        max_in_flight_requests: cli.max_in_flight,
+        json_output: cli.json || cli.output_file.is_some(),
+        output_file: cli.output_file,
+    };
+    let crawler = Arc::new(Crawler::new(&cli.url, config));
+
+    // Unbounded: the frontier is already bounded by max_depth and the
+    // visited set, and a fixed-capacity channel can deadlock a single
+    // worker that discovers more unvisited links on one page than the
+    // channel can hold (nothing else is free to drain it).
+    let (tx, rx) = mpsc::unbounded_channel::<CrawlItem>();
     let rx = Arc::new(Mutex::new(rx));
     let tx_initial = tx.clone();
 
-    tx_initial.send(base_url.to_string()).await.expect("Failed to send initial URL");
+    crawler.track_enqueued();
+    tx_initial
+        .send(CrawlItem { url: cli.url, depth: 0 })
+        .expect("Failed to send initial URL");
 
-    let workers = 5;
+    let workers = cli.workers;
+    let mut handles = Vec::with_capacity(workers);
 
     for _ in 0..workers {
         let crawler = Arc::clone(&crawler);
         let tx = tx.clone();
         let rx = Arc::clone(&rx);
+        let mut shutdown_rx = crawler.shutdown_tx.subscribe();
 
-        tokio::spawn(async move {
+        handles.push(tokio::spawn(async move {
             loop {
-                let maybe_url = {
+                if *shutdown_rx.borrow() {
+                    break;
+                }
+
+                let maybe_item = {
                     let mut rx_locked = rx.lock().await;
-                    rx_locked.recv().await
+                    tokio::select! {
+                        item = rx_locked.recv() => item,
+                        _ = shutdown_rx.changed() => None,
+                    }
                 };
-                match maybe_url {
+                match maybe_item {
                     None => break,
-                    Some(url) => crawler.process_url(url, tx.clone()).await,
+                    Some(item) => crawler.process_url(item, tx.clone()).await,
                 }
             }
-        });
+        }));
+    }
+
+    for handle in handles {
+        handle.await.expect("Worker task panicked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_backoff_delay_doubles_until_capped() {
+        let cap = Duration::from_secs(10);
+        let mut delay = Duration::from_millis(200);
+        let expected_ms = [400, 800, 1600, 3200, 6400, 10_000, 10_000];
+
+        for &ms in &expected_ms {
+            delay = next_backoff_delay(delay, 2.0, cap);
+            assert_eq!(delay, Duration::from_millis(ms));
+        }
+    }
+
+    #[test]
+    fn apply_jitter_stays_within_plus_minus_50_percent() {
+        let base = Duration::from_millis(1000);
+
+        for jitter in [-0.5, -0.25, 0.0, 0.25, 0.5] {
+            let jittered = apply_jitter(base, jitter);
+            assert!(jittered >= Duration::from_millis(500));
+            assert!(jittered <= Duration::from_millis(1500));
+        }
+    }
+
+    #[test]
+    fn apply_jitter_never_goes_negative() {
+        assert_eq!(apply_jitter(Duration::from_millis(1000), -0.5), Duration::from_millis(500));
+        assert_eq!(apply_jitter(Duration::from_millis(1000), -1.5), Duration::ZERO);
     }
-    loop {
-        tokio::time::sleep(Duration::from_secs(30)).await;
+
+    #[test]
+    fn is_disallowed_lets_a_longer_allow_override_disallow() {
+        let rules = RobotsRules {
+            disallow: vec!["/private".to_string()],
+            allow: vec!["/private/public".to_string()],
+            crawl_delay: None,
+        };
+
+        assert!(rules.is_disallowed("/private/secret"));
+        assert!(!rules.is_disallowed("/private/public/page"));
+        assert!(!rules.is_disallowed("/open"));
+    }
+
+    #[test]
+    fn is_disallowed_is_false_with_no_matching_rules() {
+        let rules = RobotsRules::default();
+        assert!(!rules.is_disallowed("/anything"));
+    }
+
+    #[test]
+    fn parse_robots_prefers_specific_user_agent_group_over_wildcard() {
+        let body = "\
+User-agent: *\n\
+Disallow: /all\n\
+Crawl-delay: 1\n\
+\n\
+User-agent: AsyncCrawler/1.0\n\
+Disallow: /specific\n\
+Crawl-delay: 3\n";
+
+        let rules = parse_robots(body, "AsyncCrawler/1.0");
+
+        assert!(rules.is_disallowed("/specific"));
+        assert!(!rules.is_disallowed("/all"));
+        assert_eq!(rules.crawl_delay, Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn parse_robots_falls_back_to_wildcard_group() {
+        let body = "User-agent: *\nDisallow: /all\nCrawl-delay: 2\n";
+
+        let rules = parse_robots(body, "AsyncCrawler/1.0");
+
+        assert!(rules.is_disallowed("/all"));
+        assert_eq!(rules.crawl_delay, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn parse_robots_ignores_comments_and_blank_values() {
+        let body = "User-agent: *\n# a comment\nDisallow: \nDisallow: /blocked # trailing comment\n";
+
+        let rules = parse_robots(body, "AsyncCrawler/1.0");
+
+        assert!(rules.is_disallowed("/blocked"));
+        assert!(!rules.is_disallowed("/open"));
     }
 }